@@ -1,35 +1,179 @@
-use std::io::{Read, Write};
-use std::net::{SocketAddr, TcpStream};
-use std::sync::{Arc, Barrier, RwLock};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime};
 
 #[cfg(debug_assertions)]
 use log::debug;
 
-use rustls::{OwnedTrustAnchor, RootCertStore};
+use rustls::client::{ServerCertVerified, ServerCertVerifier};
+use rustls::{Certificate, Error as TlsError, OwnedTrustAnchor, RootCertStore, ServerName};
+use sha2::{Digest, Sha256};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+use tokio::sync::Barrier;
+use tokio::time::timeout;
+use tokio_rustls::TlsConnector;
 use url::Url;
 
-pub trait GenericStream: Read + Write {}
+pub trait GenericStream: AsyncRead + AsyncWrite + Unpin + Send {}
 
-impl<T: Read + Write> GenericStream for T {}
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> GenericStream for T {}
 
-pub fn make_connection(
-    address: &SocketAddr,
-    url: &Url,
-    ssl: bool,
-) -> Result<Box<dyn GenericStream>, String> {
-    let mut retry = 3;
-    while retry > 0 {
-        if let Ok(stream) = TcpStream::connect_timeout(&address, Duration::from_micros(3_000_000)) {
-            #[cfg(debug_assertions)]
-            debug!("TCP connected");
+/// Number of concurrent h2 streams a transfer worker opens over its single
+/// multiplexed connection.
+const H2_STREAMS: usize = 8;
 
-            let _r = stream.set_write_timeout(Some(Duration::from_secs(3)));
-            let _r = stream.set_read_timeout(Some(Duration::from_secs(3)));
-            if !ssl {
-                return Ok(Box::new(stream));
-            }
+/// Operational knobs for connection setup and transfer sizing, so callers
+/// can tune `bim-core` for high-latency links or unusual server capacities
+/// without recompiling. Construct with [`TestConfig::builder`]; unset
+/// fields keep the library's previous hardcoded defaults.
+#[derive(Clone)]
+pub struct TestConfig {
+    connect_timeout: Duration,
+    io_timeout: Duration,
+    ping_timeout: Duration,
+    connect_retries: u32,
+    transfer_chunk_count: u64,
+}
+
+impl Default for TestConfig {
+    fn default() -> Self {
+        TestConfig {
+            connect_timeout: Duration::from_secs(3),
+            io_timeout: Duration::from_secs(3),
+            ping_timeout: Duration::from_secs(1),
+            connect_retries: 3,
+            transfer_chunk_count: 50,
+        }
+    }
+}
+
+impl TestConfig {
+    pub fn builder() -> TestConfigBuilder {
+        TestConfigBuilder::default()
+    }
+}
+
+#[derive(Default)]
+pub struct TestConfigBuilder {
+    config: TestConfig,
+}
+
+impl TestConfigBuilder {
+    /// Timeout for establishing the TCP (and, if applicable, TLS) connection.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.config.connect_timeout = timeout;
+        self
+    }
+
+    /// Timeout applied to each individual transfer read/write.
+    pub fn io_timeout(mut self, timeout: Duration) -> Self {
+        self.config.io_timeout = timeout;
+        self
+    }
+
+    /// Timeout for a single TCP ping probe.
+    pub fn ping_timeout(mut self, timeout: Duration) -> Self {
+        self.config.ping_timeout = timeout;
+        self
+    }
+
+    /// Total number of connect/handshake attempts `make_connection` makes
+    /// before giving up.
+    pub fn connect_retries(mut self, retries: u32) -> Self {
+        self.config.connect_retries = retries;
+        self
+    }
+
+    /// Base chunk count (in MiB) requested per transfer cycle in keep-alive
+    /// mode; connection-close mode requests 300 times this per cycle.
+    pub fn transfer_chunk_count(mut self, chunk_count: u64) -> Self {
+        self.config.transfer_chunk_count = chunk_count;
+        self
+    }
+
+    pub fn build(self) -> TestConfig {
+        self.config
+    }
+}
+
+/// Which set of certificates `make_connection` trusts when negotiating TLS.
+#[derive(Clone, Default)]
+pub enum TlsConfig {
+    /// Trust the bundled Mozilla root store (`webpki_roots`). The default.
+    #[default]
+    SystemRoots,
+    /// Trust only the given set of PEM-decoded CA certificates, for
+    /// self-hosted speedtest servers behind a private CA. See
+    /// [`load_pem_certificates`].
+    CustomRoots(Vec<Certificate>),
+    /// Accept any certificate the server presents. For local/dev servers
+    /// with self-signed certs; never use this against an untrusted network.
+    Insecure,
+    /// Accept only a certificate whose leaf SHA-256 fingerprint matches the
+    /// given value, regardless of chain validity.
+    Pinned([u8; 32]),
+}
+
+/// Parse PEM-encoded CA certificates for use with [`TlsConfig::CustomRoots`].
+pub fn load_pem_certificates(pem: &[u8]) -> Result<Vec<Certificate>, String> {
+    let mut reader = std::io::BufReader::new(pem);
+    rustls_pemfile::certs(&mut reader)
+        .map_err(|e| e.to_string())
+        .map(|certs| certs.into_iter().map(Certificate).collect())
+}
 
+/// A [`ServerCertVerifier`] that accepts any certificate. Backs
+/// [`TlsConfig::Insecure`].
+struct NoVerifier;
+
+impl ServerCertVerifier for NoVerifier {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        Ok(ServerCertVerified::assertion())
+    }
+}
+
+/// A [`ServerCertVerifier`] that accepts only a certificate whose leaf
+/// SHA-256 fingerprint matches the configured pin. Backs
+/// [`TlsConfig::Pinned`].
+struct PinnedCertVerifier {
+    fingerprint: [u8; 32],
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &Certificate,
+        _intermediates: &[Certificate],
+        _server_name: &ServerName,
+        _scts: &mut dyn Iterator<Item = &[u8]>,
+        _ocsp_response: &[u8],
+        _now: SystemTime,
+    ) -> Result<ServerCertVerified, TlsError> {
+        let digest = Sha256::digest(&end_entity.0);
+        if digest.as_slice() == self.fingerprint {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(TlsError::General(
+                "certificate fingerprint does not match pin".into(),
+            ))
+        }
+    }
+}
+
+fn build_client_config(tls_config: &TlsConfig) -> rustls::ClientConfig {
+    let builder = rustls::ClientConfig::builder().with_safe_defaults();
+    match tls_config {
+        TlsConfig::SystemRoots => {
             let mut root_store = RootCertStore::empty();
             root_store.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(
                 |ta| {
@@ -40,19 +184,84 @@ pub fn make_connection(
                     )
                 },
             ));
-            let config = rustls::ClientConfig::builder()
-                .with_safe_defaults()
+            builder
                 .with_root_certificates(root_store)
-                .with_no_client_auth();
+                .with_no_client_auth()
+        }
+        TlsConfig::CustomRoots(certs) => {
+            let mut root_store = RootCertStore::empty();
+            for cert in certs {
+                let _ = root_store.add(cert);
+            }
+            builder
+                .with_root_certificates(root_store)
+                .with_no_client_auth()
+        }
+        TlsConfig::Insecure => builder
+            .with_custom_certificate_verifier(Arc::new(NoVerifier))
+            .with_no_client_auth(),
+        TlsConfig::Pinned(fingerprint) => builder
+            .with_custom_certificate_verifier(Arc::new(PinnedCertVerifier {
+                fingerprint: *fingerprint,
+            }))
+            .with_no_client_auth(),
+    }
+}
 
-            let server_name = url.host_str().unwrap().try_into().unwrap();
-            let conn = rustls::ClientConnection::new(Arc::new(config), server_name).unwrap();
-            let tls = rustls::StreamOwned::new(conn, stream);
+pub async fn make_connection(
+    address: &SocketAddr,
+    url: &Url,
+    ssl: bool,
+    offer_h2: bool,
+    tls_config: &TlsConfig,
+    test_config: &TestConfig,
+) -> Result<(Box<dyn GenericStream>, Option<String>), String> {
+    let mut retry = test_config.connect_retries;
+    while retry > 0 {
+        if let Ok(Ok(stream)) =
+            timeout(test_config.connect_timeout, TcpStream::connect(address)).await
+        {
+            #[cfg(debug_assertions)]
+            debug!("TCP connected");
+
+            if !ssl {
+                return Ok((Box::new(stream), None));
+            }
+
+            let mut config = build_client_config(tls_config);
+            config.alpn_protocols = if offer_h2 {
+                vec![b"h2".to_vec(), b"http/1.1".to_vec()]
+            } else {
+                vec![b"http/1.1".to_vec()]
+            };
+
+            let server_name: ServerName = url
+                .host_str()
+                .ok_or_else(|| "url has no host".to_string())?
+                .try_into()
+                .map_err(|_e| "invalid server name".to_string())?;
+            let connector = TlsConnector::from(Arc::new(config));
+            let tls = match connector.connect(server_name, stream).await {
+                Ok(tls) => tls,
+                Err(_e) => {
+                    #[cfg(debug_assertions)]
+                    debug!("TLS handshake failed: {_e}");
+
+                    retry -= 1;
+                    continue;
+                }
+            };
+
+            let negotiated = tls
+                .get_ref()
+                .1
+                .alpn_protocol()
+                .map(|p| String::from_utf8_lossy(p).into_owned());
 
             #[cfg(debug_assertions)]
-            debug!("SSL connected");
+            debug!("SSL connected, alpn={negotiated:?}");
 
-            return Ok(Box::new(tls));
+            return Ok((Box::new(tls), negotiated));
         }
 
         retry -= 1;
@@ -60,15 +269,18 @@ pub fn make_connection(
     return Err(String::from("连接失败"));
 }
 
-pub fn request_tcp_ping(address: &SocketAddr) -> Result<u128, String> {
+pub async fn request_tcp_ping(
+    address: &SocketAddr,
+    test_config: &TestConfig,
+) -> Result<u128, String> {
     let now = Instant::now();
-    let r = TcpStream::connect_timeout(&address, Duration::from_micros(1_000_000));
+    let r = timeout(test_config.ping_timeout, TcpStream::connect(address)).await;
     let used = now.elapsed().as_micros();
     let used = match r {
-        Ok(_) => used,
-        Err(_e) => {
+        Ok(Ok(_)) => used,
+        _ => {
             #[cfg(debug_assertions)]
-            debug!("Ping {_e}");
+            debug!("Ping timed out");
 
             1_000_000
         }
@@ -76,141 +288,696 @@ pub fn request_tcp_ping(address: &SocketAddr) -> Result<u128, String> {
     Ok(used)
 }
 
-pub fn request_http_download(
-    address: SocketAddr,
+/// A connection established via [`make_connection_with_fallback`], tagged
+/// with which candidate endpoint it came from.
+pub struct FallbackConnection {
+    pub stream: Box<dyn GenericStream>,
+    pub alpn: Option<String>,
+    pub address: SocketAddr,
+    pub url: Url,
+}
+
+/// Ping every candidate endpoint, then attempt to connect starting with the
+/// lowest-latency reachable one and walking down the rest in order,
+/// returning the first endpoint that completes a connection (and, for TLS,
+/// a handshake). Useful when a primary speedtest node may be down or
+/// overloaded.
+pub async fn make_connection_with_fallback(
+    candidates: Vec<(SocketAddr, Url)>,
+    ssl: bool,
+    offer_h2: bool,
+    tls_config: &TlsConfig,
+    test_config: &TestConfig,
+) -> Result<FallbackConnection, String> {
+    if candidates.is_empty() {
+        return Err(String::from("no candidate endpoints"));
+    }
+
+    let mut probes = Vec::with_capacity(candidates.len());
+    for (address, _) in &candidates {
+        let address = *address;
+        let test_config = test_config.clone();
+        probes.push(tokio::spawn(async move {
+            request_tcp_ping(&address, &test_config)
+                .await
+                .unwrap_or(u128::MAX)
+        }));
+    }
+    let mut latencies = Vec::with_capacity(candidates.len());
+    for probe in probes {
+        latencies.push(probe.await.unwrap_or(u128::MAX));
+    }
+
+    let mut order: Vec<usize> = (0..candidates.len()).collect();
+    order.sort_by_key(|&i| latencies[i]);
+
+    #[cfg(debug_assertions)]
+    debug!("Candidate latencies (us): {latencies:?}, preferred order: {order:?}");
+
+    let mut last_err = String::from("连接失败");
+    for i in order {
+        let (address, url) = &candidates[i];
+        match make_connection(address, url, ssl, offer_h2, tls_config, test_config).await {
+            Ok((stream, alpn)) => {
+                return Ok(FallbackConnection {
+                    stream,
+                    alpn,
+                    address: *address,
+                    url: url.clone(),
+                })
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/// Drive an HTTP/2 download over the already ALPN-negotiated `stream`,
+/// fanning out `H2_STREAMS` concurrent request streams on the one
+/// multiplexed connection until `flag` is raised.
+async fn run_h2_download(
+    stream: Box<dyn GenericStream>,
     url: Url,
-    connection_close: bool,
+    counter: Arc<AtomicU64>,
+    flag: Arc<AtomicBool>,
+    test_config: TestConfig,
+) -> Result<(), String> {
+    let (send_request, connection) = h2::client::handshake(stream)
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::spawn(async move {
+        if let Err(_e) = connection.await {
+            #[cfg(debug_assertions)]
+            debug!("h2 connection error: {_e}");
+        }
+    });
+
+    let scheme = url.scheme().to_string();
+    let host_port = format!(
+        "{}:{}",
+        url.host_str().unwrap(),
+        url.port_or_known_default().unwrap()
+    );
+    let uri = format!("{scheme}://{host_port}{}", url.path());
+    let mut handles = Vec::with_capacity(H2_STREAMS);
+    for _ in 0..H2_STREAMS {
+        let mut send_request = send_request.clone();
+        let counter = counter.clone();
+        let flag = flag.clone();
+        let uri = uri.clone();
+        let host_port = host_port.clone();
+        let test_config = test_config.clone();
+        handles.push(tokio::spawn(async move {
+            while !flag.load(Ordering::Relaxed) {
+                let request = http::Request::builder()
+                    .method("GET")
+                    .uri(uri.clone())
+                    .version(http::Version::HTTP_2)
+                    .header(http::header::HOST, host_port.clone())
+                    .body(())
+                    .unwrap();
+                let (response, _) = match send_request.send_request(request, true) {
+                    Ok(r) => r,
+                    Err(_e) => break,
+                };
+                let response = match timeout(test_config.io_timeout, response).await {
+                    Ok(Ok(r)) => r,
+                    Ok(Err(_e)) => break,
+                    Err(_) => break,
+                };
+                let mut body = response.into_body();
+                while !flag.load(Ordering::Relaxed) {
+                    let chunk = match timeout(test_config.io_timeout, body.data()).await {
+                        Ok(Some(Ok(c))) => c,
+                        Ok(Some(Err(_e))) => break,
+                        Ok(None) => break,
+                        Err(_) => break,
+                    };
+                    let len = chunk.len();
+                    let _ = body.flow_control().release_capacity(len);
+                    counter.fetch_add(len as u64, Ordering::Relaxed);
+                }
+            }
+        }));
+    }
+    for h in handles {
+        let _ = h.await;
+    }
+    Ok(())
+}
+
+/// Mirror of [`run_h2_download`] for uploads: opens `H2_STREAMS` concurrent
+/// `POST` streams and keeps feeding each one a streaming body until `flag`
+/// is raised.
+async fn run_h2_upload(
+    stream: Box<dyn GenericStream>,
+    url: Url,
+    counter: Arc<AtomicU64>,
+    flag: Arc<AtomicBool>,
+    test_config: TestConfig,
+) -> Result<(), String> {
+    let (send_request, connection) = h2::client::handshake(stream)
+        .await
+        .map_err(|e| e.to_string())?;
+    tokio::spawn(async move {
+        if let Err(_e) = connection.await {
+            #[cfg(debug_assertions)]
+            debug!("h2 connection error: {_e}");
+        }
+    });
+
+    let scheme = url.scheme().to_string();
+    let host_port = format!(
+        "{}:{}",
+        url.host_str().unwrap(),
+        url.port_or_known_default().unwrap()
+    );
+    let uri = format!("{scheme}://{host_port}{}", url.path());
+    let chunk = "0123456789AaBbCcDdEeFfGgHhIiJjKkLlMmNnOoPpQqRrSsTtUuVvWwXxYyZz-="
+        .repeat(1024)
+        .into_bytes();
+    let mut handles = Vec::with_capacity(H2_STREAMS);
+    for _ in 0..H2_STREAMS {
+        let mut send_request = send_request.clone();
+        let counter = counter.clone();
+        let flag = flag.clone();
+        let uri = uri.clone();
+        let host_port = host_port.clone();
+        let chunk = chunk.clone();
+        let test_config = test_config.clone();
+        handles.push(tokio::spawn(async move {
+            while !flag.load(Ordering::Relaxed) {
+                let request = http::Request::builder()
+                    .method("POST")
+                    .uri(uri.clone())
+                    .version(http::Version::HTTP_2)
+                    .header(http::header::HOST, host_port.clone())
+                    .body(())
+                    .unwrap();
+                let (response, mut send_stream) = match send_request.send_request(request, false)
+                {
+                    Ok(r) => r,
+                    Err(_e) => break,
+                };
+                while !flag.load(Ordering::Relaxed) {
+                    if send_stream.send_data(chunk.clone().into(), false).is_err() {
+                        break;
+                    }
+                    counter.fetch_add(chunk.len() as u64, Ordering::Relaxed);
+                }
+                let _ = send_stream.send_data(Default::default(), true);
+                let _ = timeout(test_config.io_timeout, response).await;
+            }
+        }));
+    }
+    for h in handles {
+        let _ = h.await;
+    }
+    Ok(())
+}
+
+/// Read exactly `buf.len()` bytes, but treat a graceful close (a `0`-length
+/// read, which is also how rustls surfaces a TLS `close_notify`) as a clean
+/// end of stream instead of an error: returns `Ok(false)` rather than
+/// `UnexpectedEof`.
+async fn read_exact_tolerant<S: AsyncRead + Unpin>(
+    stream: &mut S,
+    buf: &mut [u8],
+) -> std::io::Result<bool> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        let n = stream.read(&mut buf[filled..]).await?;
+        if n == 0 {
+            return Ok(false);
+        }
+        filled += n;
+    }
+    Ok(true)
+}
+
+/// Which request pattern the download worker drives.
+#[derive(Clone, Copy, Default)]
+pub enum DownloadMode {
+    /// LibreSpeed-style endpoint that synthesizes a body of the requested
+    /// size from `?ckSize=&size=` query params.
+    #[default]
+    Synthetic,
+    /// Plain static file, fetched by walking it with repeated HTTP `Range`
+    /// requests of `window` bytes each, wrapping back to the start at EOF.
+    Range { window: u64 },
+}
+
+/// Connection and transfer knobs shared by [`request_http_download`] and
+/// [`request_http_upload`], bundled to keep their signatures manageable.
+/// Construct with [`TransferOptions::builder`]. `mode` is only consulted by
+/// `request_http_download`; `request_http_upload` ignores it.
+#[derive(Clone, Default)]
+pub struct TransferOptions {
     ssl: bool,
-    counter: Arc<RwLock<u128>>,
+    connection_close: bool,
+    mode: DownloadMode,
+    tls_config: TlsConfig,
+    test_config: TestConfig,
+}
+
+impl TransferOptions {
+    pub fn builder() -> TransferOptionsBuilder {
+        TransferOptionsBuilder::default()
+    }
+}
+
+#[derive(Default)]
+pub struct TransferOptionsBuilder {
+    options: TransferOptions,
+}
+
+impl TransferOptionsBuilder {
+    pub fn ssl(mut self, ssl: bool) -> Self {
+        self.options.ssl = ssl;
+        self
+    }
+
+    pub fn connection_close(mut self, connection_close: bool) -> Self {
+        self.options.connection_close = connection_close;
+        self
+    }
+
+    /// Only relevant to [`request_http_download`]; ignored by uploads.
+    pub fn mode(mut self, mode: DownloadMode) -> Self {
+        self.options.mode = mode;
+        self
+    }
+
+    pub fn tls_config(mut self, tls_config: TlsConfig) -> Self {
+        self.options.tls_config = tls_config;
+        self
+    }
+
+    pub fn test_config(mut self, test_config: TestConfig) -> Self {
+        self.options.test_config = test_config;
+        self
+    }
+
+    pub fn build(self) -> TransferOptions {
+        self.options
+    }
+}
+
+/// Read a raw HTTP response head (status line + headers) byte-by-byte up to
+/// and including the terminating blank line, without consuming any of the
+/// body that follows. The socket is unbuffered, so this is the only safe
+/// way to stop exactly at the header/body boundary.
+async fn read_response_head<S: AsyncRead + Unpin>(stream: &mut S) -> std::io::Result<Vec<u8>> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    loop {
+        let n = stream.read(&mut byte).await?;
+        if n == 0 {
+            break;
+        }
+        head.push(byte[0]);
+        if head.ends_with(b"\r\n\r\n") {
+            break;
+        }
+    }
+    Ok(head)
+}
+
+/// Parsed pieces of a `206 Partial Content` response relevant to driving a
+/// range-request cursor: the status code, and `(start, end, total)` parsed
+/// out of `Content-Range: bytes start-end/total`.
+struct RangeResponseHead {
+    status: u16,
+    content_range: Option<(u64, u64, u64)>,
+}
+
+fn parse_range_response_head(head: &[u8]) -> RangeResponseHead {
+    let text = String::from_utf8_lossy(head);
+    let mut lines = text.split("\r\n");
+    let status = lines
+        .next()
+        .and_then(|line| line.split_whitespace().nth(1))
+        .and_then(|code| code.parse().ok())
+        .unwrap_or(0);
+
+    let content_range = lines.find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if !name.eq_ignore_ascii_case("content-range") {
+            return None;
+        }
+        let value = value.trim().strip_prefix("bytes ")?;
+        let (range, total) = value.split_once('/')?;
+        let (start, end) = range.split_once('-')?;
+        Some((start.parse().ok()?, end.parse().ok()?, total.parse().ok()?))
+    });
+
+    RangeResponseHead {
+        status,
+        content_range,
+    }
+}
+
+pub async fn request_http_download(
+    candidates: Vec<(SocketAddr, Url)>,
+    options: TransferOptions,
+    counter: Arc<AtomicU64>,
     barrier: Arc<Barrier>,
-    flag: Arc<RwLock<bool>>,
+    flag: Arc<AtomicBool>,
     end: Arc<Barrier>,
 ) {
-    let chunk_count = if connection_close {
-        #[cfg(debug_assertions)]
-        debug!("Enter connection close mode");
+    let TransferOptions {
+        ssl,
+        connection_close,
+        mode,
+        tls_config,
+        test_config,
+    } = options;
 
-        15_000
-    } else {
-        50
-    };
-    let data_size = chunk_count * 1024 * 1024 as u128;
-    let mut data_counter = data_size;
-    let mut buffer = [0; 65536];
+    // Range mode talks raw HTTP/1.1 range requests over the wire, so don't
+    // let the server negotiate h2 out from under it.
+    let offer_h2 = matches!(mode, DownloadMode::Synthetic);
+
+    let conn =
+        match make_connection_with_fallback(candidates, ssl, offer_h2, &tls_config, &test_config)
+            .await
+        {
+            Ok(c) => c,
+            Err(_) => {
+                barrier.wait().await;
+                end.wait().await;
+                return;
+            }
+        };
+    let (mut stream, alpn, url) = (conn.stream, conn.alpn, conn.url);
 
     let host_port = format!(
         "{}:{}",
         url.host_str().unwrap(),
         url.port_or_known_default().unwrap()
     );
-    let path_str = url.path();
-
-    let mut stream = match make_connection(&address, &url, ssl) {
-        Ok(s) => s,
-        Err(_) => {
-            barrier.wait();
-            end.wait();
-            return;
+    let path_str = url.path().to_string();
+
+    barrier.wait().await;
+
+    if alpn.as_deref() == Some("h2") {
+        #[cfg(debug_assertions)]
+        debug!("Negotiated h2, switching to multiplexed download");
+
+        let _ = run_h2_download(stream, url, counter, flag, test_config).await;
+        end.wait().await;
+        return;
+    }
+
+    match mode {
+        DownloadMode::Synthetic => {
+            let chunk_count: u64 = if connection_close {
+                #[cfg(debug_assertions)]
+                debug!("Enter connection close mode");
+
+                test_config.transfer_chunk_count * 300
+            } else {
+                test_config.transfer_chunk_count
+            };
+            let data_size = chunk_count * 1024 * 1024;
+            let mut data_counter = data_size;
+            let mut buffer = [0; 65536];
+
+            while !flag.load(Ordering::Relaxed) {
+                if data_counter >= data_size {
+                    let now = SystemTime::now()
+                        .duration_since(SystemTime::UNIX_EPOCH)
+                        .unwrap()
+                        .as_millis();
+                    let path_query = format!(
+                        "{}?cors=true&r={}&ckSize={}&size={}",
+                        path_str, now, chunk_count, data_size
+                    );
+
+                    #[cfg(debug_assertions)]
+                    debug!("Download {path_query}");
+
+                    let request_head = format!(
+                        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: bim/1.0\r\n\r\n",
+                        path_query, host_port,
+                    )
+                    .into_bytes();
+
+                    match timeout(test_config.io_timeout, stream.write_all(&request_head)).await {
+                        Ok(Ok(_)) => {
+                            data_counter = 0;
+                        }
+                        Ok(Err(_e)) => {
+                            #[cfg(debug_assertions)]
+                            debug!("Download Error: {}", _e);
+
+                            end.wait().await;
+                            return;
+                        }
+                        Err(_) => {
+                            #[cfg(debug_assertions)]
+                            debug!("Download write timed out");
+
+                            end.wait().await;
+                            return;
+                        }
+                    }
+                } else {
+                    match timeout(
+                        test_config.io_timeout,
+                        read_exact_tolerant(&mut stream, &mut buffer),
+                    )
+                    .await
+                    {
+                        Ok(Ok(true)) => {
+                            counter.fetch_add(65536, Ordering::Relaxed);
+                            data_counter += 65536;
+                        }
+                        Ok(Ok(false)) => {
+                            #[cfg(debug_assertions)]
+                            debug!("Download connection closed by peer");
+
+                            end.wait().await;
+                            return;
+                        }
+                        Ok(Err(_e)) => {
+                            #[cfg(debug_assertions)]
+                            debug!("Download Error: {}", _e);
+
+                            end.wait().await;
+                            return;
+                        }
+                        Err(_) => {
+                            #[cfg(debug_assertions)]
+                            debug!("Download read timed out");
+
+                            end.wait().await;
+                            return;
+                        }
+                    }
+                }
+            }
         }
-    };
+        DownloadMode::Range { window } => {
+            if window == 0 {
+                #[cfg(debug_assertions)]
+                debug!("Range download window must be non-zero");
 
-    barrier.wait();
-    while !*(flag.read().unwrap()) {
-        if data_counter >= data_size {
-            let now = SystemTime::now()
-                .duration_since(SystemTime::UNIX_EPOCH)
-                .unwrap()
-                .as_millis();
-            let path_query = format!(
-                "{}?cors=true&r={}&ckSize={}&size={}",
-                path_str, now, chunk_count, data_size
-            );
+                end.wait().await;
+                return;
+            }
 
-            #[cfg(debug_assertions)]
-            debug!("Download {path_query}");
+            let mut offset: u64 = 0;
+            let mut buffer = vec![0u8; 65536];
 
-            let request_head = format!(
-                "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: bim/1.0\r\n\r\n",
-                path_query, host_port,
-            )
-            .into_bytes();
+            while !flag.load(Ordering::Relaxed) {
+                let range_end = offset + window - 1;
+                let request_head = format!(
+                    "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: bim/1.0\r\nRange: bytes={}-{}\r\n\r\n",
+                    path_str, host_port, offset, range_end,
+                )
+                .into_bytes();
 
-            match stream.write_all(&request_head) {
-                Ok(_) => {
-                    data_counter = 0;
+                #[cfg(debug_assertions)]
+                debug!("Range download bytes={offset}-{range_end}");
+
+                match timeout(test_config.io_timeout, stream.write_all(&request_head)).await {
+                    Ok(Ok(_)) => {}
+                    Ok(Err(_e)) => {
+                        #[cfg(debug_assertions)]
+                        debug!("Download Error: {}", _e);
+
+                        end.wait().await;
+                        return;
+                    }
+                    Err(_) => {
+                        #[cfg(debug_assertions)]
+                        debug!("Download write timed out");
+
+                        end.wait().await;
+                        return;
+                    }
                 }
-                Err(_e) => {
+
+                let head = match timeout(test_config.io_timeout, read_response_head(&mut stream))
+                    .await
+                {
+                    Ok(Ok(head)) if !head.is_empty() => head,
+                    Ok(Ok(_)) => {
+                        #[cfg(debug_assertions)]
+                        debug!("Download connection closed by peer");
+
+                        end.wait().await;
+                        return;
+                    }
+                    Ok(Err(_e)) => {
+                        #[cfg(debug_assertions)]
+                        debug!("Download Error: {}", _e);
+
+                        end.wait().await;
+                        return;
+                    }
+                    Err(_) => {
+                        #[cfg(debug_assertions)]
+                        debug!("Download read timed out");
+
+                        end.wait().await;
+                        return;
+                    }
+                };
+
+                let parsed = parse_range_response_head(&head);
+                if parsed.status != 206 {
                     #[cfg(debug_assertions)]
-                    debug!("Download Error: {}", _e);
+                    debug!("Unexpected range response status {}", parsed.status);
 
-                    end.wait();
+                    end.wait().await;
                     return;
                 }
-            }
-        } else {
-            match stream.read_exact(&mut buffer) {
-                Ok(_) => {
+                let Some((start, end_byte, total)) = parsed.content_range else {
+                    #[cfg(debug_assertions)]
+                    debug!("206 response missing Content-Range");
+
+                    end.wait().await;
+                    return;
+                };
+                if end_byte < start || end_byte >= total {
+                    #[cfg(debug_assertions)]
+                    debug!("206 response has an invalid Content-Range: {start}-{end_byte}/{total}");
+
+                    end.wait().await;
+                    return;
+                }
+                let body_len = (end_byte - start + 1) as usize;
+                let mut remaining = body_len;
+                while remaining > 0 && !flag.load(Ordering::Relaxed) {
+                    let to_read = remaining.min(buffer.len());
+                    match timeout(
+                        test_config.io_timeout,
+                        read_exact_tolerant(&mut stream, &mut buffer[..to_read]),
+                    )
+                    .await
                     {
-                        let mut ct = counter.write().unwrap();
-                        *ct += 65536;
+                        Ok(Ok(true)) => {
+                            counter.fetch_add(to_read as u64, Ordering::Relaxed);
+                            remaining -= to_read;
+                        }
+                        Ok(Ok(false)) => {
+                            #[cfg(debug_assertions)]
+                            debug!("Download connection closed by peer mid-body");
+
+                            end.wait().await;
+                            return;
+                        }
+                        Ok(Err(_e)) => {
+                            #[cfg(debug_assertions)]
+                            debug!("Download Error: {}", _e);
+
+                            end.wait().await;
+                            return;
+                        }
+                        Err(_) => {
+                            #[cfg(debug_assertions)]
+                            debug!("Download read timed out");
+
+                            end.wait().await;
+                            return;
+                        }
                     }
-                    data_counter += 65536;
                 }
-                Err(_e) => {
-                    #[cfg(debug_assertions)]
-                    debug!("Download Error: {}", _e);
 
-                    end.wait();
-                    return;
+                offset = end_byte + 1;
+                if offset >= total {
+                    offset = 0;
                 }
             }
         }
     }
-    end.wait();
+    end.wait().await;
 }
 
-pub fn request_http_upload(
-    address: SocketAddr,
-    url: Url,
-    connection_close: bool,
-    ssl: bool,
-    counter: Arc<RwLock<u128>>,
+pub async fn request_http_upload(
+    candidates: Vec<(SocketAddr, Url)>,
+    options: TransferOptions,
+    counter: Arc<AtomicU64>,
     barrier: Arc<Barrier>,
-    flag: Arc<RwLock<bool>>,
+    flag: Arc<AtomicBool>,
     end: Arc<Barrier>,
 ) {
-    let chunk_count = if connection_close {
+    let TransferOptions {
+        ssl,
+        connection_close,
+        tls_config,
+        test_config,
+        ..
+    } = options;
+
+    let chunk_count: u64 = if connection_close {
         #[cfg(debug_assertions)]
         debug!("Enter connection close mode");
 
-        15_000
+        test_config.transfer_chunk_count * 300
     } else {
-        50
+        test_config.transfer_chunk_count
     };
-    let data_size = chunk_count * 1024 * 1024 as u128;
+    let data_size = chunk_count * 1024 * 1024;
     let mut data_counter = data_size;
 
+    let request_chunk = "0123456789AaBbCcDdEeFfGgHhIiJjKkLlMmNnOoPpQqRrSsTtUuVvWwXxYyZz-="
+        .repeat(1024)
+        .into_bytes();
+
+    let conn =
+        match make_connection_with_fallback(candidates, ssl, true, &tls_config, &test_config)
+            .await
+        {
+            Ok(c) => c,
+            Err(_) => {
+                barrier.wait().await;
+                end.wait().await;
+                return;
+            }
+        };
+    let (mut stream, alpn, url) = (conn.stream, conn.alpn, conn.url);
+
     let host_port = format!(
         "{}:{}",
         url.host_str().unwrap(),
         url.port_or_known_default().unwrap()
     );
-    let url_path = url.path();
-    let request_chunk = "0123456789AaBbCcDdEeFfGgHhIiJjKkLlMmNnOoPpQqRrSsTtUuVvWwXxYyZz-="
-        .repeat(1024)
-        .into_bytes();
+    let url_path = url.path().to_string();
 
-    let mut stream = match make_connection(&address, &url, ssl) {
-        Ok(s) => s,
-        Err(_) => {
-            barrier.wait();
-            end.wait();
-            return;
-        }
-    };
+    barrier.wait().await;
 
-    barrier.wait();
-    while !*(flag.read().unwrap()) {
+    if alpn.as_deref() == Some("h2") {
+        #[cfg(debug_assertions)]
+        debug!("Negotiated h2, switching to multiplexed upload");
+
+        let _ = run_h2_upload(stream, url, counter, flag, test_config).await;
+        end.wait().await;
+        return;
+    }
+
+    while !flag.load(Ordering::Relaxed) {
         if data_counter >= data_size {
             let now = SystemTime::now()
                 .duration_since(SystemTime::UNIX_EPOCH)
@@ -227,41 +994,98 @@ pub fn request_http_upload(
             )
             .into_bytes();
 
-            match stream.write_all(&request_head) {
-                Ok(_) => {
-                    {
-                        let mut ct = counter.write().unwrap();
-                        *ct += request_head.len() as u128;
-                    }
-
+            match timeout(test_config.io_timeout, stream.write_all(&request_head)).await {
+                Ok(Ok(_)) => {
+                    counter.fetch_add(request_head.len() as u64, Ordering::Relaxed);
                     data_counter = 0;
                 }
-                Err(_e) => {
+                Ok(Err(_e)) => {
                     #[cfg(debug_assertions)]
                     debug!("Upload Error: {}", _e);
 
-                    end.wait();
+                    end.wait().await;
+                    return;
+                }
+                Err(_) => {
+                    #[cfg(debug_assertions)]
+                    debug!("Upload write timed out");
+
+                    end.wait().await;
                     return;
                 }
             }
         } else {
-            match stream.write_all(&request_chunk) {
-                Ok(_) => {
-                    {
-                        let mut ct = counter.write().unwrap();
-                        *ct += 65536;
-                    }
+            match timeout(test_config.io_timeout, stream.write_all(&request_chunk)).await {
+                Ok(Ok(_)) => {
+                    counter.fetch_add(65536, Ordering::Relaxed);
                     data_counter += 65536;
                 }
-                Err(_e) => {
+                Ok(Err(_e)) => {
                     #[cfg(debug_assertions)]
                     debug!("Upload Error: {}", _e);
 
-                    end.wait();
+                    end.wait().await;
+                    return;
+                }
+                Err(_) => {
+                    #[cfg(debug_assertions)]
+                    debug!("Upload write timed out");
+
+                    end.wait().await;
                     return;
                 }
             }
         }
     }
-    end.wait();
+    end.wait().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_valid_206_response() {
+        let head = b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-65535/1000000\r\n\r\n";
+        let parsed = parse_range_response_head(head);
+        assert_eq!(parsed.status, 206);
+        assert_eq!(parsed.content_range, Some((0, 65535, 1000000)));
+    }
+
+    #[test]
+    fn parses_status_from_non_206_response() {
+        let head = b"HTTP/1.1 416 Range Not Satisfiable\r\n\r\n";
+        let parsed = parse_range_response_head(head);
+        assert_eq!(parsed.status, 416);
+        assert_eq!(parsed.content_range, None);
+    }
+
+    #[test]
+    fn missing_content_range_header_yields_none() {
+        let head = b"HTTP/1.1 206 Partial Content\r\nContent-Type: application/octet-stream\r\n\r\n";
+        let parsed = parse_range_response_head(head);
+        assert_eq!(parsed.status, 206);
+        assert_eq!(parsed.content_range, None);
+    }
+
+    #[test]
+    fn inverted_range_still_parses_so_the_caller_can_reject_it() {
+        let head = b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 100-0/1000000\r\n\r\n";
+        let parsed = parse_range_response_head(head);
+        assert_eq!(parsed.content_range, Some((100, 0, 1000000)));
+    }
+
+    #[test]
+    fn out_of_bounds_range_still_parses_so_the_caller_can_reject_it() {
+        let head = b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes 0-999999/1000\r\n\r\n";
+        let parsed = parse_range_response_head(head);
+        assert_eq!(parsed.content_range, Some((0, 999999, 1000)));
+    }
+
+    #[test]
+    fn malformed_content_range_value_yields_none() {
+        let head = b"HTTP/1.1 206 Partial Content\r\nContent-Range: bytes oops\r\n\r\n";
+        let parsed = parse_range_response_head(head);
+        assert_eq!(parsed.content_range, None);
+    }
 }